@@ -0,0 +1,72 @@
+use std::fmt;
+use std::io;
+
+/// The various errors that can be returned during the decryption process.
+#[derive(Debug)]
+pub enum Error {
+    /// The age header's MAC did not match the expected value.
+    DecryptionFailed,
+    /// The decryption attempt exceeded the allowed memory budget.
+    ///
+    /// This occurs when the scrypt work factor embedded in a passphrase
+    /// recipient's stanza would require more memory than the caller's
+    /// allowed fraction of this machine's available memory (by default, more
+    /// than a quarter of it; see `max_memory_fraction` on
+    /// `RecipientStanza::unwrap_file_key`), and is rejected before the KDF is
+    /// attempted.
+    ExcessiveMemory {
+        /// The amount of memory scrypt would need to evaluate the embedded
+        /// work factor.
+        required_bytes: u64,
+        /// The amount of memory available on this machine, as best we could
+        /// tell.
+        available_bytes: u64,
+    },
+    /// The decryption attempt exceeded the maximum supported work factor.
+    ExcessiveWork {
+        /// The work factor required to decrypt the oldest file key.
+        required: u8,
+        /// The work factor this device computes in about one second; unless
+        /// the caller sets its own ceiling, work factors up to four above it
+        /// are accepted.
+        target: u8,
+    },
+    /// An I/O error occurred while reading or writing a file.
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<chacha20poly1305::aead::Error> for Error {
+    fn from(_: chacha20poly1305::aead::Error) -> Self {
+        Error::DecryptionFailed
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DecryptionFailed => write!(f, "Decryption failed"),
+            Error::ExcessiveMemory {
+                required_bytes,
+                available_bytes,
+            } => write!(
+                f,
+                "Recipient requires too much memory to unwrap ({} bytes needed, {} available)",
+                required_bytes, available_bytes
+            ),
+            Error::ExcessiveWork { required, .. } => write!(
+                f,
+                "Recipient requires work factor {}, which is too large",
+                required
+            ),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
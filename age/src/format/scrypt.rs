@@ -1,6 +1,12 @@
-use age_core::{format::AgeStanza, primitives::aead_decrypt};
+use age_core::{
+    format::AgeStanza,
+    primitives::{aead_decrypt, aead_encrypt},
+};
+use bytesize::ByteSize;
 use secrecy::{ExposeSecret, Secret, SecretString};
+use std::cell::Cell;
 use std::convert::TryInto;
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use crate::{error::Error, keys::FileKey, primitives::scrypt, util::read::base64_arg};
@@ -12,43 +18,296 @@ const ONE_SECOND: Duration = Duration::from_secs(1);
 const SALT_LEN: usize = 16;
 const ENCRYPTED_FILE_KEY_BYTES: usize = 32;
 
+/// log2 of scrypt's per-unit-of-N memory overhead for r=8, p=1: peak usage is
+/// roughly `128 * r * N` bytes, i.e. `2^(log_n + 10)`.
+const SCRYPT_MEM_LOG2_OVERHEAD: u32 = 10;
+
+/// The default fraction of available memory a single scrypt invocation is
+/// allowed to claim, when the caller does not choose one explicitly via
+/// [`RecipientStanza::unwrap_file_key`]. Conservative, since decryption may
+/// run alongside other work.
+const DEFAULT_MAX_MEMORY_FRACTION: u64 = 4;
+
+/// Returns the peak memory, in bytes, that scrypt will need to evaluate the
+/// given work factor.
+fn scrypt_memory_bytes(log_n: u8) -> u64 {
+    1u128
+        .checked_shl(u32::from(log_n) + SCRYPT_MEM_LOG2_OVERHEAD)
+        .unwrap_or(u128::MAX)
+        .min(u64::MAX as u128) as u64
+}
+
+thread_local! {
+    // Lets tests (and batch tools running under a memory limit a platform
+    // probe can't see, e.g. a cgroup) force a specific available-memory
+    // figure without depending on the host machine's actual RAM.
+    static AVAILABLE_MEMORY_OVERRIDE: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Overrides the available-memory estimate used by [`available_memory_bytes`]
+/// on the current thread, for deterministic tests of the memory ceiling (or
+/// for batch tools that know their own memory budget better than a
+/// best-effort platform probe can).
+pub fn set_available_memory_bytes(bytes: u64) {
+    AVAILABLE_MEMORY_OVERRIDE.with(|cell| cell.set(Some(bytes)));
+}
+
+/// Estimates the memory available to this process, in bytes.
+///
+/// Reads `/proc/meminfo` on Linux. Falls back to a conservative estimate on
+/// other platforms (or if that query fails), so the memory ceiling still has
+/// an effect rather than being silently skipped.
+fn available_memory_bytes() -> u64 {
+    if let Some(bytes) = AVAILABLE_MEMORY_OVERRIDE.with(Cell::get) {
+        return bytes;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(bytes) = linux_available_memory_bytes() {
+            return bytes;
+        }
+    }
+
+    // Couldn't measure (non-Linux, or /proc/meminfo was unreadable or
+    // unparseable): assume a conservative 1 GiB available, so headers
+    // demanding far more are still rejected.
+    1 << 30
+}
+
+/// Parses `MemAvailable` out of `/proc/meminfo`, in bytes.
+#[cfg(target_os = "linux")]
+fn linux_available_memory_bytes() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib.saturating_mul(1024))
+}
+
+/// The work factor used as a reference point when timing scrypt throughput.
+/// Chosen to always be fast, regardless of machine speed.
+const REFERENCE_LOG_N: u8 = 10;
+
+/// The smallest work factor scrypt will accept (N = 2^log_n must be > 1).
+const MIN_LOG_N: u8 = 1;
+
+/// Times how long a single reference-sized scrypt evaluation takes on this
+/// device, to be used as a proxy for CPU throughput (which scales linearly
+/// with N).
+///
+/// Returns `None` on platforms where wall-clock time cannot be measured.
+fn time_reference_scrypt() -> Option<Duration> {
+    // Platforms that have a functional SystemTime::now():
+    #[cfg(not(all(target_arch = "wasm32", not(target_os = "wasi"))))]
+    {
+        use std::time::SystemTime;
+        let start = SystemTime::now();
+        scrypt(&[], REFERENCE_LOG_N, "").expect("log_n < 64");
+        SystemTime::now().duration_since(start).ok()
+    }
+
+    // Platforms where SystemTime::now() panics:
+    #[cfg(all(target_arch = "wasm32", not(target_os = "wasi")))]
+    {
+        None
+    }
+}
+
+/// A one-shot measurement of this machine's scrypt throughput, used to derive
+/// work-factor ceilings without re-benchmarking on every call.
+#[derive(Debug, Clone, Copy)]
+struct ScryptCalibration {
+    /// How many reference-sized (`log_n = REFERENCE_LOG_N`) scrypt
+    /// evaluations this machine can perform in one second.
+    reference_ops_per_second: f64,
+}
+
+impl ScryptCalibration {
+    fn measure() -> Self {
+        let reference_ops_per_second = time_reference_scrypt()
+            .map(|d| 1.0 / d.as_secs_f64())
+            .unwrap_or_else(|| {
+                // Couldn't measure, so guess a throughput that reproduces the
+                // historical ~1 second default at log_n = 18.
+                2f64.powi(18 - i32::from(REFERENCE_LOG_N))
+            });
+
+        ScryptCalibration {
+            reference_ops_per_second,
+        }
+    }
+
+    /// Derives the largest work factor expected to complete within `target`.
+    /// Never returns less than `REFERENCE_LOG_N`, even where that work factor
+    /// takes longer than `target`.
+    ///
+    /// Guaranteed to return a valid work factor (less than 64).
+    fn log_n_for_duration(&self, target: Duration) -> u8 {
+        let mut log_n = REFERENCE_LOG_N;
+        let mut ops_per_second = self.reference_ops_per_second;
+
+        while ops_per_second / 2.0 >= 1.0 / target.as_secs_f64() && log_n < 63 {
+            log_n += 1;
+            ops_per_second /= 2.0;
+        }
+
+        log_n
+    }
+
+    /// Estimates how long evaluating `log_n` would take on this machine.
+    ///
+    /// Clamps to [`Duration::MAX`] instead of panicking if `log_n` (or a
+    /// corrupt calibration) would produce a value outside `Duration`'s range;
+    /// callers such as [`RecipientStanza::cost`] must stay panic-free on
+    /// attacker-controlled `log_n` values.
+    fn duration_for_log_n(&self, log_n: u8) -> Duration {
+        let scale = 2f64.powi(i32::from(log_n) - i32::from(REFERENCE_LOG_N));
+        let secs = scale / self.reference_ops_per_second;
+        if secs.is_finite() && secs >= 0.0 && secs < Duration::MAX.as_secs_f64() {
+            Duration::from_secs_f64(secs)
+        } else {
+            Duration::MAX
+        }
+    }
+}
+
+// Process-wide and calibrated at most once, as the request asks: a batch
+// tool decrypting many files across worker threads should pay scrypt's
+// reference-timing cost a single time for the whole process, not once per
+// thread.
+static CALIBRATION: OnceLock<ScryptCalibration> = OnceLock::new();
+
+thread_local! {
+    // Tests need every test to see its own deterministic throughput figure
+    // without racing each other to be the first to populate `CALIBRATION`
+    // (which, being process-wide, would otherwise let whichever test runs
+    // first silently win for every other test in the binary). This override
+    // is checked before the process-wide cache, but never populates it.
+    static CALIBRATION_OVERRIDE: Cell<Option<ScryptCalibration>> = const { Cell::new(None) };
+}
+
+/// Returns the process's cached scrypt throughput calibration (or this
+/// thread's test override, if one has been set), measuring it on first use.
+fn calibration() -> ScryptCalibration {
+    CALIBRATION_OVERRIDE
+        .with(Cell::get)
+        .unwrap_or_else(|| *CALIBRATION.get_or_init(ScryptCalibration::measure))
+}
+
+/// Overrides the calibration used by calls to [`calibration`] on the current
+/// thread, so that the work factor bounds it derives are deterministic.
+///
+/// Intended for tests. Unlike the process-wide calibration this shadows, an
+/// override may be set repeatedly and only affects the thread that set it;
+/// it never contends with or clobbers the one-shot process calibration that
+/// production code (and other threads) relies on.
+pub fn set_scrypt_calibration(reference_ops_per_second: f64) {
+    CALIBRATION_OVERRIDE.with(|cell| {
+        cell.set(Some(ScryptCalibration {
+            reference_ops_per_second,
+        }));
+    });
+}
+
 /// Pick an scrypt work factor that will take around 1 second on this device.
 ///
 /// Guaranteed to return a valid work factor (less than 64).
 fn target_scrypt_work_factor() -> u8 {
-    // Time a work factor that should always be fast.
-    let mut log_n = 10;
-
-    let duration: Option<Duration> = {
-        // Platforms that have a functional SystemTime::now():
-        #[cfg(not(all(target_arch = "wasm32", not(target_os = "wasi"))))]
-        {
-            use std::time::SystemTime;
-            let start = SystemTime::now();
-            scrypt(&[], log_n, "").expect("log_n < 64");
-            SystemTime::now().duration_since(start).ok()
+    calibration().log_n_for_duration(ONE_SECOND)
+}
+
+/// Parameters controlling the scrypt work factor chosen when wrapping a file
+/// key with a passphrase, expressed in terms a caller actually cares about:
+/// how long encryption should take, and how much memory decryption may need.
+///
+/// # Examples
+///
+/// ```ignore
+/// use std::time::Duration;
+/// use bytesize::ByteSize;
+///
+/// // Spend about 3 seconds, but never need more than 512 MiB to decrypt.
+/// let params = ScryptParams::new(Duration::from_secs(3)).max_memory(ByteSize::mib(512));
+/// let log_n = params.resolve();
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct ScryptParams {
+    target_duration: Duration,
+    max_memory: Option<ByteSize>,
+}
+
+impl Default for ScryptParams {
+    /// Targets around 1 second, with no memory limit.
+    fn default() -> Self {
+        ScryptParams {
+            target_duration: ONE_SECOND,
+            max_memory: None,
         }
+    }
+}
 
-        // Platforms where SystemTime::now() panics:
-        #[cfg(all(target_arch = "wasm32", not(target_os = "wasi")))]
-        {
-            None
+impl ScryptParams {
+    /// Creates scrypt parameters that target the given wall-clock duration.
+    pub fn new(target_duration: Duration) -> Self {
+        ScryptParams {
+            target_duration,
+            ..ScryptParams::default()
         }
-    };
-
-    duration
-        .map(|mut d| {
-            // Use duration as a proxy for CPU usage, which scales linearly with N.
-            while d < ONE_SECOND && log_n < 63 {
-                log_n += 1;
-                d *= 2;
+    }
+
+    /// Caps the memory scrypt is allowed to use when decrypting, even if the
+    /// time budget would otherwise allow a larger work factor.
+    pub fn max_memory(mut self, max_memory: ByteSize) -> Self {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    /// Solves for the largest work factor that fits within both the time and
+    /// memory budgets, using this process's cached throughput calibration
+    /// (see [`set_scrypt_calibration`]). The time budget alone never brings the
+    /// result below `REFERENCE_LOG_N`; only the memory cap can.
+    ///
+    /// Guaranteed to return a valid work factor (less than 64).
+    pub fn resolve(&self) -> u8 {
+        let mut log_n = calibration().log_n_for_duration(self.target_duration);
+
+        if let Some(max_memory) = self.max_memory {
+            while log_n > MIN_LOG_N && scrypt_memory_bytes(log_n) > max_memory.as_u64() {
+                log_n -= 1;
             }
-            log_n
-        })
-        .unwrap_or({
-            // Couldn't measure, so guess. This is roughly 1 second on a modern machine.
-            18
-        })
+        }
+
+        log_n
+    }
+}
+
+/// The estimated cost of unwrapping a scrypt-wrapped file key, derived from
+/// the embedded work factor without running the KDF.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScryptCost {
+    /// The work factor (`log_n`) embedded in the recipient stanza.
+    pub log_n: u8,
+    /// The estimated peak memory scrypt will need to evaluate `log_n`.
+    pub memory_bytes: u64,
+    /// The estimated wall-clock time scrypt will need, based on this
+    /// process's cached throughput calibration.
+    pub time: Duration,
+}
+
+/// Estimates the cost of running scrypt at the given work factor, without
+/// running the KDF.
+///
+/// `log_n` is typically read straight off a recipient stanza's embedded work
+/// factor (see [`RecipientStanza::cost`]); unlike `RecipientStanza` itself,
+/// this takes only a `u8`, so it has no crate-private types in its signature
+/// and is reachable from outside the crate (re-exported as
+/// `age::scrypt::cost_for_log_n`).
+pub fn cost_for_log_n(log_n: u8) -> ScryptCost {
+    ScryptCost {
+        log_n,
+        memory_bytes: scrypt_memory_bytes(log_n),
+        time: calibration().duration_for_log_n(log_n),
+    }
 }
 
 #[derive(Debug)]
@@ -66,6 +325,13 @@ impl RecipientStanza {
 
         let salt = base64_arg(stanza.args.get(0)?, [0; SALT_LEN])?;
         let log_n = u8::from_str_radix(stanza.args.get(1)?, 10).ok()?;
+        // scrypt's N = 2^log_n must fit the `log_n < 64` invariant the rest of
+        // this module relies on; reject anything else as a malformed stanza
+        // rather than letting an attacker-chosen log_n reach the KDF or the
+        // cost-estimation helpers below.
+        if log_n >= 64 {
+            return None;
+        }
 
         Some(RecipientStanza {
             salt,
@@ -74,10 +340,58 @@ impl RecipientStanza {
         })
     }
 
+    /// Wraps `file_key` with `passphrase`, choosing a work factor via
+    /// `params`. Called by [`crate::scrypt::Recipient::wrap_file_key`], the
+    /// public passphrase-recipient API that drives this at encryption time.
+    pub(crate) fn wrap_file_key(
+        file_key: &FileKey,
+        passphrase: &SecretString,
+        params: ScryptParams,
+    ) -> Self {
+        let log_n = params.resolve();
+
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt).expect("Should always succeed");
+
+        let mut inner_salt = vec![];
+        inner_salt.extend_from_slice(SCRYPT_SALT_LABEL);
+        inner_salt.extend_from_slice(&salt);
+
+        let enc_key =
+            scrypt(&inner_salt, log_n, passphrase.expose_secret()).expect("log_n < 64");
+        let encrypted_file_key = aead_encrypt(&enc_key, file_key.0.expose_secret())[..]
+            .try_into()
+            .expect("correct length");
+
+        RecipientStanza {
+            salt,
+            log_n,
+            encrypted_file_key,
+        }
+    }
+
+    /// Estimates the cost of unwrapping this stanza's file key, without
+    /// running the KDF or attempting decryption.
+    ///
+    /// Intended for callers that want to warn a user (e.g. "this file needs
+    /// ~1 GiB / ~6 s to open; proceed?") or pre-filter maliciously inflated
+    /// headers before committing to [`unwrap_file_key`](RecipientStanza::unwrap_file_key).
+    pub fn cost(&self) -> ScryptCost {
+        cost_for_log_n(self.log_n)
+    }
+
+    /// Unwraps the file key sealed in this stanza.
+    ///
+    /// `max_work_factor` bounds the CPU time we will accept, defaulting to
+    /// roughly 16 seconds' worth of work relative to this device. `max_memory_fraction`
+    /// bounds the fraction of available memory a single unwrap may claim,
+    /// defaulting to [`DEFAULT_MAX_MEMORY_FRACTION`]; e.g. `Some(2)` allows at
+    /// most half of available memory, `Some(1)` allows all of it.
     pub(crate) fn unwrap_file_key(
         &self,
         passphrase: &SecretString,
         max_work_factor: Option<u8>,
+        max_memory_fraction: Option<u64>,
     ) -> Result<Option<FileKey>, Error> {
         // Place bounds on the work factor we will accept (roughly 16 seconds).
         let target = target_scrypt_work_factor();
@@ -88,6 +402,18 @@ impl RecipientStanza {
             });
         }
 
+        let max_memory_fraction = max_memory_fraction
+            .unwrap_or(DEFAULT_MAX_MEMORY_FRACTION)
+            .max(1);
+        let cost = self.cost();
+        let available_bytes = available_memory_bytes();
+        if cost.memory_bytes > available_bytes / max_memory_fraction {
+            return Err(Error::ExcessiveMemory {
+                required_bytes: cost.memory_bytes,
+                available_bytes,
+            });
+        }
+
         let mut inner_salt = vec![];
         inner_salt.extend_from_slice(SCRYPT_SALT_LABEL);
         inner_salt.extend_from_slice(&self.salt);
@@ -110,6 +436,23 @@ impl RecipientStanza {
     }
 }
 
+/// Estimates the cost of unwrapping a parsed header's scrypt recipient
+/// stanza, if it has one, without running the KDF or attempting decryption.
+///
+/// `stanzas` is typically a header's full set of recipient stanzas; at most
+/// one is ever scrypt (passphrase encryption is exclusive with other
+/// recipients), so the first match is returned.
+///
+/// `RecipientStanza` is crate-internal, so this stays `pub(crate)`; the
+/// external equivalent a downstream caller can actually reach is
+/// [`cost_for_log_n`] (re-exported as `age::scrypt::cost_for_log_n`), which
+/// needs only the `log_n` this function's result already carries.
+pub(crate) fn header_scrypt_cost<'a>(
+    stanzas: impl IntoIterator<Item = &'a RecipientStanza>,
+) -> Option<ScryptCost> {
+    stanzas.into_iter().next().map(RecipientStanza::cost)
+}
+
 pub(super) mod write {
     use age_core::format::write::age_stanza;
     use cookie_factory::{SerializeFn, WriteContext};
@@ -128,3 +471,85 @@ pub(super) mod write {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `log_n` is attacker-controlled (it comes straight off the wire via
+    /// `from_stanza`), so cost estimation must never panic regardless of its
+    /// value, even if some other bug let an out-of-range `log_n` through.
+    #[test]
+    fn cost_does_not_panic_for_any_log_n() {
+        for log_n in 0..=u8::MAX {
+            let stanza = RecipientStanza {
+                salt: [0; SALT_LEN],
+                log_n,
+                encrypted_file_key: [0; ENCRYPTED_FILE_KEY_BYTES],
+            };
+            let cost = stanza.cost();
+            assert!(cost.time <= Duration::MAX);
+        }
+    }
+
+    #[test]
+    fn from_stanza_rejects_out_of_range_log_n() {
+        let stanza = AgeStanza {
+            tag: SCRYPT_RECIPIENT_TAG,
+            args: vec!["AAAAAAAAAAAAAAAAAAAAAA", "200"],
+            body: vec![0; ENCRYPTED_FILE_KEY_BYTES],
+        };
+        assert!(RecipientStanza::from_stanza(stanza).is_none());
+    }
+
+    /// Pinning the calibration on this test's thread makes `log_n_for_duration`
+    /// fully deterministic, regardless of the host machine's real throughput.
+    #[test]
+    fn calibration_override_is_deterministic_on_this_thread() {
+        set_scrypt_calibration(2f64.powi(20)); // 2^20 reference ops/sec
+        assert_eq!(
+            calibration().log_n_for_duration(Duration::from_secs(1)),
+            REFERENCE_LOG_N + 20
+        );
+    }
+
+    #[test]
+    fn scrypt_params_resolve_respects_memory_cap() {
+        set_scrypt_calibration(2f64.powi(30)); // fast enough to want a huge log_n
+        let uncapped = ScryptParams::new(Duration::from_secs(1)).resolve();
+        let capped = ScryptParams::new(Duration::from_secs(1))
+            .max_memory(ByteSize::mib(1))
+            .resolve();
+        assert!(capped <= uncapped);
+        assert!(scrypt_memory_bytes(capped) <= ByteSize::mib(1).as_u64());
+    }
+
+    /// Regression test: the memory-capping loop used to floor at
+    /// `REFERENCE_LOG_N` (10) instead of the true minimum work factor, so a
+    /// cap below `scrypt_memory_bytes(10)` (1 MiB) was silently ignored.
+    #[test]
+    fn scrypt_params_resolve_respects_memory_cap_below_reference_log_n() {
+        set_scrypt_calibration(2f64.powi(30));
+        let capped = ScryptParams::new(Duration::from_secs(1))
+            .max_memory(ByteSize::kib(64))
+            .resolve();
+        assert!(capped < REFERENCE_LOG_N);
+        assert!(scrypt_memory_bytes(capped) <= ByteSize::kib(64).as_u64());
+    }
+
+    #[test]
+    fn unwrap_file_key_rejects_excessive_memory() {
+        set_available_memory_bytes(1024); // 1 KiB available
+        let stanza = RecipientStanza {
+            salt: [0; SALT_LEN],
+            log_n: 30,
+            encrypted_file_key: [0; ENCRYPTED_FILE_KEY_BYTES],
+        };
+        let passphrase = SecretString::new("hunter2".to_owned());
+
+        let err = stanza
+            .unwrap_file_key(&passphrase, Some(63), None)
+            .expect_err("should reject before attempting the KDF");
+        assert!(matches!(err, Error::ExcessiveMemory { .. }));
+    }
+}
@@ -0,0 +1,6 @@
+use secrecy::Secret;
+
+/// The symmetric key used to decrypt (and re-encrypt, per recipient) a
+/// file's payload, once it has been unwrapped from a recipient stanza.
+#[derive(Clone)]
+pub(crate) struct FileKey(pub(crate) Secret<[u8; 16]>);
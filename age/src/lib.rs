@@ -0,0 +1,8 @@
+//! *Actually good age encryption*... bindings in Rust.
+
+mod error;
+mod format;
+mod keys;
+pub mod scrypt;
+
+pub use error::Error;
@@ -0,0 +1,93 @@
+//! Passphrase-based file encryption, using scrypt for key-stretching.
+
+use secrecy::SecretString;
+
+use crate::{error::Error, format::scrypt::RecipientStanza, keys::FileKey};
+
+// Re-exported so downstream callers can actually name these items: all are
+// defined in the crate-internal `format::scrypt` module, but are themselves
+// fully `pub`, so re-exporting them here (the crate's public passphrase API)
+// doesn't leak anything `format::scrypt` doesn't already expose on purpose.
+pub use crate::format::scrypt::{
+    cost_for_log_n, set_available_memory_bytes, set_scrypt_calibration, ScryptCost, ScryptParams,
+};
+
+/// A passphrase-based recipient. Wraps a file key by stretching the
+/// passphrase with scrypt, using the work factor chosen by `params`.
+pub struct Recipient {
+    passphrase: SecretString,
+    params: ScryptParams,
+}
+
+impl Recipient {
+    /// Creates a passphrase recipient that targets scrypt's default work
+    /// factor (around 1 second, with no memory limit).
+    pub fn new(passphrase: SecretString) -> Self {
+        Recipient {
+            passphrase,
+            params: ScryptParams::default(),
+        }
+    }
+
+    /// Overrides the scrypt work factor used when wrapping the file key, e.g.
+    /// to target a specific duration or cap memory usage.
+    pub fn with_params(mut self, params: ScryptParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub(crate) fn wrap_file_key(&self, file_key: &FileKey) -> RecipientStanza {
+        RecipientStanza::wrap_file_key(file_key, &self.passphrase, self.params)
+    }
+
+    /// Estimates the cost of decrypting a file encrypted with this
+    /// recipient's chosen parameters, without running the KDF.
+    ///
+    /// Useful for telling a user up front roughly how much memory and time
+    /// opening the file will need later, e.g. "this will need ~1 GiB / ~6 s
+    /// to decrypt".
+    pub fn cost(&self) -> ScryptCost {
+        cost_for_log_n(self.params.resolve())
+    }
+}
+
+/// A passphrase-based identity, used to unwrap a file key sealed by a
+/// [`Recipient`] with a matching passphrase.
+pub struct Identity {
+    passphrase: SecretString,
+    max_work_factor: Option<u8>,
+    max_memory_fraction: Option<u64>,
+}
+
+impl Identity {
+    /// Creates a passphrase identity with the default CPU and memory
+    /// ceilings (roughly 16 seconds of work, and a quarter of available
+    /// memory).
+    pub fn new(passphrase: SecretString) -> Self {
+        Identity {
+            passphrase,
+            max_work_factor: None,
+            max_memory_fraction: None,
+        }
+    }
+
+    /// Overrides the largest scrypt work factor this identity will accept.
+    pub fn with_max_work_factor(mut self, max_work_factor: u8) -> Self {
+        self.max_work_factor = Some(max_work_factor);
+        self
+    }
+
+    /// Overrides the fraction of available memory a single unwrap attempt
+    /// may claim, e.g. `2` allows at most half of available memory.
+    pub fn with_max_memory_fraction(mut self, max_memory_fraction: u64) -> Self {
+        self.max_memory_fraction = Some(max_memory_fraction);
+        self
+    }
+
+    pub(crate) fn unwrap_file_key(
+        &self,
+        stanza: &RecipientStanza,
+    ) -> Result<Option<FileKey>, Error> {
+        stanza.unwrap_file_key(&self.passphrase, self.max_work_factor, self.max_memory_fraction)
+    }
+}